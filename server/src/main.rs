@@ -1,30 +1,34 @@
 use anyhow::Result;
-use num_enum::IntoPrimitive;
-use num_enum::TryFromPrimitive;
+use clap::Parser;
 use quinn::RecvStream;
 use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
 use quinn::{ClientConfig, Endpoint, ServerConfig};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
 use rustls::{RootCertStore, ServerConfig as RustlsServerConfig};
 use rustls_pemfile::{certs, private_key};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, Cursor};
+use std::hash::{Hash, Hasher};
+use std::io::BufReader;
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::time::Duration;
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
     sync::Arc,
 };
+use tokio::net::UdpSocket;
 use tokio::sync::watch;
 use tokio::time::timeout;
 use tracing::debug;
 use tracing::trace;
 use tracing::warn;
 use tracing_subscriber::EnvFilter;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 // macro_rules! encode_uints {
 //     ($buf:expr, $($val:expr),+) => {{
@@ -46,22 +50,50 @@ fn load_key(path: &Path) -> PrivateKeyDer<'static> {
     private_key(&mut reader).unwrap().unwrap()
 }
 
-fn make_server_config() -> Result<(ServerConfig, CertificateDer<'static>)> {
-    let certs_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let cert_path = certs_dir.join("server.crt");
-    let key_path = certs_dir.join("server.key");
+/// How the server authenticates connecting clients.
+///
+/// With [`ClientAuth::None`] anyone may connect and is handed an anonymous,
+/// counter-derived identity. With [`ClientAuth::RequireCa`] the TLS handshake
+/// requires a client certificate chaining to the given CA root, and the player
+/// identity is derived from that certificate so it survives reconnects.
+enum ClientAuth {
+    None,
+    RequireCa(PathBuf),
+}
+
+impl ClientAuth {
+    /// Fallback for anonymous play: no client certificate is requested.
+    fn with_no_client_auth() -> Self {
+        ClientAuth::None
+    }
+}
 
-    let cert_chain = load_certs(&cert_path);
+fn make_server_config(
+    cert_path: &Path,
+    key_path: &Path,
+    client_auth: &ClientAuth,
+) -> Result<(ServerConfig, CertificateDer<'static>)> {
+    let cert_chain = load_certs(cert_path);
     let server_cert = cert_chain
         .first()
         .cloned()
         .ok_or_else(|| anyhow::anyhow!("no certificates loaded"))?;
-    let key = load_key(&key_path);
-
-    let mut rustls_config = RustlsServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key)
-        .unwrap();
+    let key = load_key(key_path);
+
+    let builder = RustlsServerConfig::builder();
+    let mut rustls_config = match client_auth {
+        ClientAuth::None => builder.with_no_client_auth(),
+        ClientAuth::RequireCa(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path) {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    }
+    .with_single_cert(cert_chain, key)
+    .unwrap();
     rustls_config.alpn_protocols = vec![b"h3".to_vec()];
 
     let quic_crypto = QuicServerConfig::try_from(rustls_config).unwrap();
@@ -71,6 +103,21 @@ fn make_server_config() -> Result<(ServerConfig, CertificateDer<'static>)> {
     Ok((server_config, server_cert))
 }
 
+/// Derive a stable player identity from a presented client certificate.
+///
+/// The certificate's subject is hashed rather than the whole DER, so a renewed
+/// or rotated certificate for the same player (same subject, new serial or
+/// validity) still maps to the same id across reconnects. Falls back to the raw
+/// certificate bytes if the subject cannot be parsed.
+fn player_id_from_cert(cert: &CertificateDer<'_>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match X509Certificate::from_der(cert.as_ref()) {
+        Ok((_, parsed)) => parsed.subject().as_raw().hash(&mut hasher),
+        Err(_) => cert.as_ref().hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
 fn make_client_config(cert_path: &Path) -> ClientConfig {
     let certs = load_certs(cert_path);
 
@@ -108,25 +155,162 @@ pub fn make_client_endpoint(bind_addr: SocketAddr) -> Result<Endpoint> {
 /// - a stream of incoming QUIC connections
 /// - server certificate serialized into DER format
 #[allow(unused)]
-pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, CertificateDer<'static>)> {
+pub fn make_server_endpoint(
+    bind_addr: SocketAddr,
+    cert_path: &Path,
+    key_path: &Path,
+    client_auth: &ClientAuth,
+) -> Result<(Endpoint, CertificateDer<'static>)> {
     //let (server_config, server_cert) = configure_server()?;
-    let (server_config, server_cert) = make_server_config()?;
+    let (server_config, server_cert) = make_server_config(cert_path, key_path, client_auth)?;
     let endpoint = Endpoint::server(server_config, bind_addr)?;
     Ok((endpoint, server_cert))
 }
 
-#[repr(u8)]
-#[derive(IntoPrimitive, TryFromPrimitive)]
-enum NetCmd {
-    Pass = 0,
-    YouAre = 1,
-    Turn = 2,
-    MoveTo = 3,
+/// Multicast group the LAN discovery beacon joins and clients query.
+const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+const DISCOVERY_PORT: u16 = 5001;
+/// 4-byte magic prefixing every discovery datagram. The following version byte
+/// makes the fixed-layout INFO packet self-describing so fields can be appended
+/// compatibly.
+const DISCOVERY_MAGIC: [u8; 4] = *b"DNGN";
+const DISCOVERY_VERSION: u8 = 1;
+/// Fixed INFO packet layout: magic(4) + version(1) + port(2) + turn(4) + players(2).
+const INFO_PACKET_LEN: usize = 4 + 1 + 2 + 4 + 2;
+
+/// A dungeon server found on the LAN via the discovery beacon.
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    /// Address the INFO packet was received from.
+    pub addr: SocketAddr,
+    pub version: u8,
+    /// QUIC listen port advertised by the server.
+    pub port: u16,
+    /// Turn number at the time the reply was sent.
+    pub turn: u32,
+    /// Number of players currently connected.
+    pub players: u16,
+}
+
+impl ServerInfo {
+    fn decode(addr: SocketAddr, buf: &[u8]) -> Option<ServerInfo> {
+        if buf.len() < INFO_PACKET_LEN || buf[0..4] != DISCOVERY_MAGIC {
+            return None;
+        }
+        Some(ServerInfo {
+            addr,
+            version: buf[4],
+            port: u16::from_be_bytes([buf[5], buf[6]]),
+            turn: u32::from_be_bytes([buf[7], buf[8], buf[9], buf[10]]),
+            players: u16::from_be_bytes([buf[11], buf[12]]),
+        })
+    }
+}
+
+/// Spawn the LAN discovery beacon alongside the QUIC endpoint.
+///
+/// Joins the well-known multicast group and replies to any datagram beginning
+/// with [`DISCOVERY_MAGIC`] with a fixed-layout INFO packet describing this
+/// server, so clients can present a server list before connecting.
+fn spawn_discovery_beacon(
+    quic_port: u16,
+    turn: Arc<AtomicU32>,
+    state: Arc<Mutex<GameState>>,
+) -> Result<()> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+    socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    tokio::spawn(async move {
+        let mut buf = [0u8; 16];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Discovery recv error: {e}");
+                    continue;
+                }
+            };
+            if len < 4 || buf[0..4] != DISCOVERY_MAGIC {
+                continue;
+            }
+            let players = state.lock().unwrap().players.len() as u16;
+            let turn = turn.load(Ordering::SeqCst);
+            let mut reply = Vec::with_capacity(INFO_PACKET_LEN);
+            reply.extend_from_slice(&DISCOVERY_MAGIC);
+            reply.push(DISCOVERY_VERSION);
+            reply.extend_from_slice(&quic_port.to_be_bytes());
+            reply.extend_from_slice(&turn.to_be_bytes());
+            reply.extend_from_slice(&players.to_be_bytes());
+            if let Err(e) = socket.send_to(&reply, from).await {
+                warn!("Discovery reply error: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Broadcast a discovery query to the LAN and collect replies until `timeout_dur`.
+///
+/// Lets a client UI present a server list before calling [`make_client_endpoint`].
+#[allow(unused)]
+pub async fn discover(timeout_dur: Duration) -> Result<Vec<ServerInfo>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_loop_v4(true)?;
+    socket
+        .send_to(&DISCOVERY_MAGIC, (DISCOVERY_GROUP, DISCOVERY_PORT))
+        .await?;
+
+    let mut servers = Vec::new();
+    let mut buf = [0u8; 64];
+    let deadline = tokio::time::Instant::now() + timeout_dur;
+    loop {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        match timeout(remaining, socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, from))) => {
+                if let Some(info) = ServerInfo::decode(from, &buf[..len]) {
+                    servers.push(info);
+                }
+            }
+            Ok(Err(_)) | Err(_) => break,
+        }
+    }
+    Ok(servers)
+}
+
+/// Position of a single player within a [`ServerMsg::Snapshot`].
+///
+/// `left` marks a player removed via [`Command::TimeoutPlayer`] on this tick so
+/// clients can drop them from the render.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct PlayerState {
+    id: u64,
+    x: u32,
+    y: u32,
+    left: bool,
+}
+
+/// Messages sent from the server to each client, msgpack-encoded behind the
+/// 2-byte length frame.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+enum ServerMsg {
+    YouAre(u64),
+    /// Per-turn positions of every player. When `full` is set this is a
+    /// keyframe carrying the whole table; otherwise it is a delta listing only
+    /// the players whose position changed since the last broadcast.
+    Snapshot {
+        turn: u32,
+        full: bool,
+        players: Vec<PlayerState>,
+    },
 }
 
 type Dir = u8;
 type RelPos = (u8, u8);
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 enum Command {
     Wait,
     AddPlayer,
@@ -136,6 +320,28 @@ enum Command {
     Attack(RelPos),
 }
 
+/// The subset of actions a client is allowed to send over the wire. The
+/// server-internal lifecycle commands (`AddPlayer`/`TimeoutPlayer`) are
+/// deliberately absent so a client cannot drive its own join/removal.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+enum ClientCmd {
+    Wait,
+    Move(Dir),
+    MoveTo(u32, u32),
+    Attack(RelPos),
+}
+
+impl From<ClientCmd> for Command {
+    fn from(cmd: ClientCmd) -> Self {
+        match cmd {
+            ClientCmd::Wait => Command::Wait,
+            ClientCmd::Move(dir) => Command::Move(dir),
+            ClientCmd::MoveTo(x, y) => Command::MoveTo(x, y),
+            ClientCmd::Attack(rel) => Command::Attack(rel),
+        }
+    }
+}
+
 struct Player {
     x: u32,
     y: u32,
@@ -145,64 +351,194 @@ struct GameState {
     players: HashMap<u64, Player>,
 }
 
-/// Create a framed msgpack packet
-macro_rules! make_packet {
-    ($($val:expr),+) => {{
-        let count = [$(stringify!($val)),+].len();
-        let mut buf : Vec<u8> = vec![0,0];
-        _ = rmp::encode::write_array_len(&mut buf, count as u32);
-        $(
-            _ = rmp::encode::write_uint(&mut buf, $val as u64);
-        )+
-        let t = u16::to_be_bytes((buf.len() - 2) as u16);
-        buf[0..2].copy_from_slice(&t);
-        buf
-    }};
+/// Serialize `msg` to msgpack and prepend the 2-byte big-endian length frame
+/// that [`read_packet`] expects.
+fn frame<T: Serialize>(msg: &T) -> Vec<u8> {
+    let body = rmp_serde::to_vec(msg).unwrap();
+    let mut buf = Vec::with_capacity(body.len() + 2);
+    buf.extend_from_slice(&u16::to_be_bytes(body.len() as u16));
+    buf.extend_from_slice(&body);
+    buf
+}
+
+/// Emit a full keyframe snapshot this often so late joiners and resyncs see the
+/// whole table; the turns in between carry position deltas only.
+const KEYFRAME_INTERVAL: u32 = 30;
+
+/// Upper bound on a single framed packet, set by the 2-byte length prefix.
+///
+/// Server→client snapshots — whose keyframes grow with the player count — are
+/// bounded by this, not by the per-command `max_packet` that sizes the server's
+/// command read buffer. A client must size its receive buffer to this so a
+/// realistic keyframe round-trips rather than tripping [`read_packet`]'s guard.
+const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+
+/// Build a framed [`ServerMsg::Snapshot`] for the current turn.
+///
+/// A keyframe (every [`KEYFRAME_INTERVAL`] turns) lists every player; other
+/// turns list only players whose position changed since the last broadcast,
+/// plus any players in `left` that were removed this tick. `last` is updated to
+/// the positions just broadcast.
+fn build_snapshot(
+    turn: u32,
+    state: &GameState,
+    last: &mut HashMap<u64, (u32, u32)>,
+    left: &mut Vec<u64>,
+) -> Vec<u8> {
+    let full = turn % KEYFRAME_INTERVAL == 0;
+    let mut players = Vec::new();
+    for (&id, p) in &state.players {
+        let pos = (p.x, p.y);
+        if full || last.get(&id) != Some(&pos) {
+            players.push(PlayerState {
+                id,
+                x: p.x,
+                y: p.y,
+                left: false,
+            });
+        }
+        last.insert(id, pos);
+    }
+    for id in left.drain(..) {
+        players.push(PlayerState {
+            id,
+            x: 0,
+            y: 0,
+            left: true,
+        });
+        last.remove(&id);
+    }
+    frame(&ServerMsg::Snapshot {
+        turn,
+        full,
+        players,
+    })
 }
 
 async fn read_packet(recv_stream: &mut RecvStream, target: &mut [u8]) -> Result<usize> {
     let mut t = [0u8; 2];
     recv_stream.read_exact(&mut t).await?;
     let len = u16::from_be_bytes(t) as usize;
+    if len > target.len() {
+        anyhow::bail!("packet length {len} exceeds buffer {}", target.len());
+    }
     recv_stream.read_exact(&mut target[..len]).await?;
     Ok(len)
 }
 
-fn decode_packet(source: &[u8]) -> Vec<i64> {
-    let mut cursor = Cursor::new(source);
-    let len = rmp::decode::read_array_len(&mut cursor).unwrap();
-    let mut result = Vec::new();
-    for _ in 0..len {
-        let val: i64 = rmp::decode::read_int(&mut cursor).unwrap();
-        result.push(val);
-    }
-    debug!("Decoded packet {result:?}");
-    result
+/// Tunables threaded into [`run_server`].
+#[derive(Clone)]
+struct GameConfig {
+    max_players: usize,
+    max_packet: usize,
+    turn_timeout: Duration,
+    /// Whether to run the LAN discovery beacon alongside the QUIC endpoint.
+    discovery: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-
-    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
-
-    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
-    let (endpoint, _server_cert) = make_server_endpoint(server_addr)?;
-
+/// Spawn the accept loop and turn coordinator for `endpoint`, returning the
+/// accept-loop handle. Factored out of `main` so tests can drive the server
+/// in-process against an ephemeral port.
+fn run_server(endpoint: Endpoint, config: GameConfig) -> tokio::task::JoinHandle<()> {
     let state = Arc::new(Mutex::new(GameState {
         players: HashMap::new(),
     }));
 
-    // From ooordinator to all clients; turn no and bytes to send
+    // Shared turn counter exposed to the discovery beacon.
+    let turn_counter = Arc::new(AtomicU32::new(0));
+    if config.discovery {
+        let port = endpoint.local_addr().map(|a| a.port()).unwrap_or_default();
+        if let Err(e) = spawn_discovery_beacon(port, turn_counter.clone(), state.clone()) {
+            warn!("Discovery beacon disabled: {e}");
+        }
+    }
+
+    // From coordinator to all clients; turn no and bytes to send
     let (turn_tx, turn_rx) = watch::channel::<(usize, Vec<u8>)>((0, vec![]));
 
     // From client handler to coordinator
     let (cmd_tx, mut cmd_rx) = tokio::sync::mpsc::channel::<(u64, Command)>(128);
 
+    // Turn coordinator
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut ids = HashSet::new();
+            ids.insert(0);
+            let mut turn = 0;
+            // Positions broadcast on the previous turn, for computing deltas, and
+            // the players that left during the current collection round.
+            let mut last_positions: HashMap<u64, (u32, u32)> = HashMap::new();
+            let mut left_this_tick: Vec<u64> = Vec::new();
+            // Last position of players removed via TimeoutPlayer, so a reconnect
+            // with the same stable id is restored instead of reset to 0,0.
+            let mut last_known: HashMap<u64, (u32, u32)> = HashMap::new();
+            loop {
+                if state.lock().unwrap().players.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                } else {
+                    debug!("Turn {turn}");
+                    let buf = {
+                        let s = state.lock().unwrap();
+                        build_snapshot(turn as u32, &s, &mut last_positions, &mut left_this_tick)
+                    };
+                    turn_tx.send((turn, buf)).unwrap();
+                    turn += 1;
+                    turn_counter.store(turn as u32, Ordering::SeqCst);
+                }
+                // Get all client commands
+                loop {
+                    let (id, cmd) = cmd_rx.recv().await.unwrap();
+                    debug!("Client {id} reported {:?}", cmd);
+                    {
+                        let mut s = state.lock().unwrap();
+                        match cmd {
+                            // Restore the last known position of a reconnecting
+                            // player; only never-before-seen players start at 0,0.
+                            Command::AddPlayer => {
+                                let (x, y) = last_known.remove(&id).unwrap_or((0, 0));
+                                _ = s.players.entry(id).or_insert(Player { x, y });
+                            }
+                            Command::TimeoutPlayer => {
+                                if let Some(player) = s.players.remove(&id) {
+                                    last_known.insert(id, (player.x, player.y));
+                                    left_this_tick.push(id);
+                                    debug!("Removed player {id}");
+                                }
+                            }
+
+                            Command::MoveTo(x, y) => {
+                                // A player that was already timed-out may still send
+                                // a late move; ignore it rather than panicking.
+                                if let Some(player) = s.players.get_mut(&id) {
+                                    player.x = x;
+                                    player.y = y;
+                                }
+                            }
+                            // Wait is a silent no-op; Move/Attack deserialize but
+                            // are not yet wired into game logic — log so a dropped
+                            // action is observable rather than vanishing.
+                            Command::Wait => (),
+                            other => warn!("Client {id} dropped unhandled action {other:?}"),
+                        }
+                    }
+                    ids.insert(id);
+                    if ids.len() >= state.lock().unwrap().players.len() {
+                        debug!("All clients reported");
+                        // All clients have reported in
+                        ids.clear();
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
     // Server accept loop
-    let handle = tokio::spawn(async move {
+    let max_players = config.max_players;
+    let max_packet = config.max_packet;
+    let turn_timeout = config.turn_timeout;
+    tokio::spawn(async move {
         let player_count: Arc<AtomicU64> = Arc::new(0.into());
         loop {
             let incoming_conn = endpoint.accept().await.unwrap();
@@ -211,53 +547,62 @@ async fn main() -> Result<()> {
             let player_count = player_count.clone();
             let mut turn_rx = turn_rx.clone();
             let cmd_tx = cmd_tx.clone();
+            let accept_state = state.clone();
             // Client loop
             tokio::spawn(async move {
                 let (mut send_stream, mut recv_stream) = conn.open_bi().await.unwrap();
-                let mut target = vec![0; 128];
-                let id = player_count.fetch_add(1, Ordering::SeqCst);
+                let mut target = vec![0; max_packet];
+                // Prefer a stable identity derived from the client certificate so a
+                // reconnecting player rejoins their existing slot; fall back to the
+                // connection counter for anonymous play.
+                let id = conn
+                    .peer_identity()
+                    .and_then(|any| any.downcast::<Vec<CertificateDer<'static>>>().ok())
+                    .and_then(|chain| chain.first().map(player_id_from_cert))
+                    .unwrap_or_else(|| player_count.fetch_add(1, Ordering::SeqCst));
+                // Refuse new players once the table is full; reconnecting players
+                // keep their existing slot.
+                {
+                    let players = &accept_state.lock().unwrap().players;
+                    if !players.contains_key(&id) && players.len() >= max_players {
+                        warn!("Rejecting player {id}: server full ({max_players})");
+                        return;
+                    }
+                }
                 cmd_tx.send((id, Command::AddPlayer)).await.unwrap();
 
-                let buf = make_packet!(NetCmd::YouAre, id);
-                send_stream.write(&buf).await.unwrap();
+                let buf = frame(&ServerMsg::YouAre(id));
+                send_stream.write_all(&buf).await.unwrap();
                 debug!("Player {id} loop starting");
 
                 while turn_rx.changed().await.is_ok() {
                     let (turn, data) = turn_rx.borrow_and_update().clone();
                     if !data.is_empty() {
-                        let res = send_stream.write(&data).await;
-                        if let Err(_) = res {
+                        let res = send_stream.write_all(&data).await;
+                        if res.is_err() {
                             cmd_tx.send((id, Command::TimeoutPlayer)).await.unwrap();
                             break;
                         }
                     }
                     debug!("Player {id} turn {turn}");
-                    // let bytes = rmp_serde::to_vec(&msg).unwrap();
-                    // send_stream.write(&bytes).await.unwrap();
                     let mut command: Option<Command> = None;
                     while command.is_none() {
-                        if let Ok(res) = timeout(
-                            Duration::from_secs(1),
-                            read_packet(&mut recv_stream, &mut target),
-                        )
-                        .await
+                        if let Ok(res) =
+                            timeout(turn_timeout, read_packet(&mut recv_stream, &mut target)).await
                         {
                             match res {
                                 Ok(count) => {
                                     trace!("Read {:x?}", &target[0..count]);
-                                    let packet = decode_packet(&target[..count]);
-                                    match NetCmd::try_from(packet[0] as u8) {
-                                        Ok(NetCmd::MoveTo) => {
-                                            let x = packet[1] as u32;
-                                            let y = packet[2] as u32;
-                                            trace!("Move To {x} {y}");
-                                            command = Some(Command::MoveTo(x, y));
+                                    match rmp_serde::from_slice::<ClientCmd>(&target[..count]) {
+                                        Ok(cmd) => {
+                                            let cmd: Command = cmd.into();
+                                            trace!("Client command {cmd:?}");
+                                            command = Some(cmd);
                                         }
-                                        Ok(NetCmd::Pass) => {
-                                            command = Some(Command::Wait);
+                                        Err(e) => {
+                                            warn!("Decode error: {e}");
+                                            command = Some(Command::TimeoutPlayer);
                                         }
-                                        Ok(NetCmd::Turn) => {}
-                                        _ => {}
                                     }
                                 }
                                 Err(e) => {
@@ -278,72 +623,299 @@ async fn main() -> Result<()> {
                 }
             });
         }
-    });
+    })
+}
 
-    // Server main loop
-    let state = state.clone();
-    tokio::spawn(async move {
-        let mut ids = HashSet::new();
-        ids.insert(0);
-        let mut turn = 0;
-        loop {
-            if state.lock().unwrap().players.is_empty() {
-                tokio::time::sleep(Duration::from_millis(100)).await;
-            } else {
-                debug!("Turn {turn}");
-                let buf = make_packet!(NetCmd::Turn, turn);
-                turn_tx.send((turn, buf)).unwrap();
-                turn += 1;
-            }
-            // Get all client commands
-            loop {
-                let (id, cmd) = cmd_rx.recv().await.unwrap();
-                debug!("Client {id} reported {:?}", cmd);
-                {
-                    let mut s = state.lock().unwrap();
-                    match cmd {
-                        Command::AddPlayer => _ = s.players.insert(id, Player { x: 0, y: 0 }),
-                        Command::TimeoutPlayer => {
-                            _ = s.players.remove(&id);
-                            debug!("Removed player {id}");
-                        }
+/// Runtime configuration for the dungeon server.
+#[derive(Parser, Debug)]
+#[command(name = "dungeon-server")]
+struct Opt {
+    /// Address the QUIC server binds to.
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    listen: SocketAddr,
+    /// TLS certificate chain in PEM format (defaults to `server.crt` in the crate directory).
+    #[arg(long)]
+    cert: Option<PathBuf>,
+    /// TLS private key in PEM format (defaults to `server.key` in the crate directory).
+    #[arg(long)]
+    key: Option<PathBuf>,
+    /// CA root enabling client-certificate auth; anonymous play when omitted.
+    #[arg(long)]
+    client_ca: Option<PathBuf>,
+    /// Maximum number of simultaneous players.
+    #[arg(long, default_value_t = 64)]
+    max_players: usize,
+    /// Per-turn command timeout in milliseconds.
+    #[arg(long, default_value_t = 1000)]
+    turn_timeout_ms: u64,
+    /// Maximum size in bytes of a client→server command packet (the server's
+    /// command read buffer). Server→client snapshots are bounded separately by
+    /// [`MAX_FRAME_SIZE`].
+    #[arg(long, default_value_t = 128)]
+    max_packet: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
-                        Command::MoveTo(x, y) => {
-                            let player = s.players.get_mut(&id).unwrap();
-                            player.x = x;
-                            player.y = y;
-                            let _buf = make_packet!(NetCmd::MoveTo, id, player.x, player.y);
+    let opt = Opt::parse();
+
+    let server_addr = opt.listen;
+    let certs_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let cert_path = opt.cert.unwrap_or_else(|| certs_dir.join("server.crt"));
+    let key_path = opt.key.unwrap_or_else(|| certs_dir.join("server.key"));
+    let client_auth = match opt.client_ca {
+        Some(ca) => ClientAuth::RequireCa(ca),
+        None => ClientAuth::with_no_client_auth(),
+    };
+    let (endpoint, _server_cert) =
+        make_server_endpoint(server_addr, &cert_path, &key_path, &client_auth)?;
+
+    let config = GameConfig {
+        max_players: opt.max_players,
+        max_packet: opt.max_packet,
+        turn_timeout: Duration::from_millis(opt.turn_timeout_ms),
+        discovery: true,
+    };
+
+    run_server(endpoint, config).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::SendStream;
+    use std::sync::Once;
+
+    fn install_crypto() {
+        static ONCE: Once = Once::new();
+        ONCE.call_once(|| {
+            let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        });
+    }
+
+    fn test_config() -> GameConfig {
+        GameConfig {
+            max_players: 16,
+            max_packet: 128,
+            turn_timeout: Duration::from_millis(200),
+            discovery: false,
+        }
+    }
+
+    /// Generate an ephemeral self-signed `localhost` certificate so the harness
+    /// is self-contained and needs no committed cert fixtures.
+    fn generate_test_certs() -> (PathBuf, PathBuf) {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let n = NEXT.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let cert_path = dir.join(format!("dungeon-test-{pid}-{n}.crt"));
+        let key_path = dir.join(format!("dungeon-test-{pid}-{n}.key"));
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Bind a server to an ephemeral localhost port, returning its address and
+    /// the self-signed certificate clients should trust.
+    fn spawn_test_server() -> (SocketAddr, PathBuf) {
+        let (cert_path, key_path) = generate_test_certs();
+        let (endpoint, _cert) = make_server_endpoint(
+            (Ipv4Addr::LOCALHOST, 0).into(),
+            &cert_path,
+            &key_path,
+            &ClientAuth::None,
+        )
+        .unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        run_server(endpoint, test_config());
+        (addr, cert_path)
+    }
+
+    /// A client endpoint trusting `cert_path` as its only root.
+    fn make_test_client_endpoint(cert_path: &Path) -> Result<Endpoint> {
+        let mut endpoint = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into())?;
+        endpoint.set_default_client_config(make_client_config(cert_path));
+        Ok(endpoint)
+    }
+
+    /// Read a single framed [`ServerMsg`] from a client receive stream. The
+    /// buffer is sized to [`MAX_FRAME_SIZE`] so full keyframes round-trip
+    /// regardless of player count.
+    async fn read_msg(recv: &mut RecvStream) -> Result<ServerMsg> {
+        let mut target = vec![0u8; MAX_FRAME_SIZE];
+        let count = read_packet(recv, &mut target).await?;
+        Ok(rmp_serde::from_slice(&target[..count])?)
+    }
+
+    /// Open a client connection and consume its initial `YouAre` message.
+    async fn connect_client(
+        endpoint: &Endpoint,
+        addr: SocketAddr,
+    ) -> Result<(u64, SendStream, RecvStream)> {
+        let conn = endpoint.connect(addr, "localhost")?.await?;
+        let (send, mut recv) = conn.accept_bi().await?;
+        let id = match read_msg(&mut recv).await? {
+            ServerMsg::YouAre(id) => id,
+            other => anyhow::bail!("expected YouAre, got {other:?}"),
+        };
+        Ok((id, send, recv))
+    }
+
+    /// What a client observed over a run: its own id, the running view of every
+    /// player's last known position, and the player table from the most recent
+    /// full keyframe (an unambiguous, non-racy cross-client checkpoint).
+    struct ClientObservation {
+        id: u64,
+        view: HashMap<u64, (u32, u32)>,
+        last_keyframe: Option<HashMap<u64, (u32, u32)>>,
+    }
+
+    /// Drive a client for `rounds` turns, replying with the scripted command on
+    /// each turn (padding with `Wait`), and folding the broadcast snapshots into
+    /// a [`ClientObservation`].
+    async fn drive_client(
+        endpoint: &Endpoint,
+        addr: SocketAddr,
+        script: Vec<ClientCmd>,
+        rounds: usize,
+    ) -> Result<ClientObservation> {
+        let (id, mut send, mut recv) = connect_client(endpoint, addr).await?;
+        let mut view: HashMap<u64, (u32, u32)> = HashMap::new();
+        let mut last_keyframe: Option<HashMap<u64, (u32, u32)>> = None;
+        let mut last_turn: Option<u32> = None;
+        for r in 0..rounds {
+            match read_msg(&mut recv).await? {
+                ServerMsg::Snapshot {
+                    turn,
+                    full,
+                    players,
+                } => {
+                    if let Some(prev) = last_turn {
+                        assert!(turn >= prev, "turn went backwards: {prev} -> {turn}");
+                    }
+                    last_turn = Some(turn);
+                    if full {
+                        // A keyframe carries the whole table, so it is the full
+                        // state as of this turn regardless of join order.
+                        last_keyframe = Some(
+                            players
+                                .iter()
+                                .filter(|p| !p.left)
+                                .map(|p| (p.id, (p.x, p.y)))
+                                .collect(),
+                        );
+                    }
+                    for p in players {
+                        if p.left {
+                            view.remove(&p.id);
+                        } else {
+                            view.insert(p.id, (p.x, p.y));
                         }
-                        _ => (),
                     }
                 }
-                ids.insert(id);
-                if ids.len() >= state.lock().unwrap().players.len() {
-                    debug!("All clients reported");
-                    // All clients have reported in
-                    ids.clear();
-                    break;
-                }
+                other => anyhow::bail!("expected Snapshot, got {other:?}"),
             }
+            let cmd = script.get(r).cloned().unwrap_or(ClientCmd::Wait);
+            send.write_all(&frame(&cmd)).await?;
         }
-    });
+        Ok(ClientObservation {
+            id,
+            view,
+            last_keyframe,
+        })
+    }
 
-    // let endpoint = make_client_endpoint("0.0.0.0:0".parse()?)?;
-    // // connect to server
-    // let connection = endpoint.connect(server_addr, "localhost")?.await?;
-    // println!("[client] connected: addr={}", connection.remote_address());
-    //
-    // // Waiting for a stream will complete with an error when the server closes the connection
-    // let (mut s, mut r) = connection.accept_bi().await?;
-    // let mut target = vec![0; 128];
-    // if let Some(count) = r.read(&mut target).await? {
-    //     println!("READ {count} {}", target[0]);
-    //     //s.write(&target[0..1]).await?;
-    // }
-    //
-    // // Make sure the server has a chance to clean up
-    // endpoint.wait_idle().await;
-
-    _ = handle.await?;
-    Ok(())
+    #[tokio::test]
+    async fn e2e_turn_sync_and_positions() -> Result<()> {
+        install_crypto();
+        let (addr, cert_path) = spawn_test_server();
+        let endpoint = make_test_client_endpoint(&cert_path)?;
+
+        // Each client moves to a distinct cell once, then idles.
+        let scripts = [
+            vec![ClientCmd::MoveTo(1, 1)],
+            vec![ClientCmd::MoveTo(2, 2)],
+            vec![ClientCmd::MoveTo(3, 3)],
+        ];
+        // Run past one keyframe so every client observes a full-table snapshot
+        // taken while all three are connected and settled.
+        let rounds = (KEYFRAME_INTERVAL as usize) + 6;
+
+        let mut handles = Vec::new();
+        for script in scripts {
+            let endpoint = endpoint.clone();
+            handles.push(tokio::spawn(async move {
+                drive_client(&endpoint, addr, script, rounds).await
+            }));
+        }
+
+        let mut observations = Vec::new();
+        for handle in handles {
+            observations.push(handle.await??);
+        }
+
+        // Assert against the last keyframe, not the post-loop view: clients
+        // finish and disconnect on staggered turns, so a running client can see
+        // an earlier-finisher's `left` flag and drop its position otherwise.
+        let expected: HashSet<(u32, u32)> = [(1, 1), (2, 2), (3, 3)].into_iter().collect();
+        for obs in &observations {
+            let keyframe = obs
+                .last_keyframe
+                .as_ref()
+                .expect("client should observe at least one keyframe");
+            let positions: HashSet<(u32, u32)> = keyframe.values().copied().collect();
+            assert_eq!(
+                positions, expected,
+                "client {} should observe every player's final position",
+                obs.id
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timeout_path_removes_silent_player() -> Result<()> {
+        install_crypto();
+        let (addr, cert_path) = spawn_test_server();
+        let endpoint = make_test_client_endpoint(&cert_path)?;
+
+        // Survivor keeps reporting across many turns.
+        let survivor_ep = endpoint.clone();
+        let survivor = tokio::spawn(async move {
+            drive_client(&survivor_ep, addr, vec![ClientCmd::MoveTo(5, 5)], 20).await
+        });
+
+        // Quitter reports twice, then goes silent without disconnecting.
+        let quitter_ep = endpoint.clone();
+        let quitter = tokio::spawn(async move {
+            let (id, mut send, mut recv) = connect_client(&quitter_ep, addr).await?;
+            for _ in 0..2 {
+                let _ = read_msg(&mut recv).await?;
+                send.write_all(&frame(&ClientCmd::Wait)).await?;
+            }
+            // Stay connected but stop sending so the coordinator must time us out.
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok::<u64, anyhow::Error>(id)
+        });
+
+        let quitter_id = quitter.await??;
+        let survivor = survivor.await??;
+
+        // The survivor advanced through all its turns (no stall) and saw the
+        // quitter leave via the snapshot `left` flag.
+        assert!(
+            !survivor.view.contains_key(&quitter_id),
+            "timed-out player should have been removed from the broadcast"
+        );
+        Ok(())
+    }
 }